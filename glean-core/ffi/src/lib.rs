@@ -1,21 +1,84 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
 use std::os::raw::c_char;
+use std::sync::{Mutex, Once};
+use std::time::Instant;
 
 use ffi_support::{
-    call_with_output, define_handle_map_deleter, define_string_destructor, ConcurrentHandleMap,
-    ExternError, FfiStr, IntoFfi,
+    call_with_output, call_with_result, define_handle_map_deleter, define_string_destructor,
+    ConcurrentHandleMap, ExternError, FfiStr, IntoFfi,
 };
 use lazy_static::lazy_static;
 
-use glean_core::{metrics::*, CommonMetricData, Glean};
+use glean_core::{metrics::*, ping::PingType, CommonMetricData, Glean};
+
+mod upload;
 
 lazy_static! {
     static ref BOOLEAN_METRICS: ConcurrentHandleMap<BooleanMetric> = ConcurrentHandleMap::new();
     static ref STRING_METRICS: ConcurrentHandleMap<StringMetric> = ConcurrentHandleMap::new();
     static ref COUNTER_METRICS: ConcurrentHandleMap<CounterMetric> = ConcurrentHandleMap::new();
+    static ref EVENT_METRICS: ConcurrentHandleMap<EventMetric> = ConcurrentHandleMap::new();
+    static ref PING_TYPES: ConcurrentHandleMap<PingType> = ConcurrentHandleMap::new();
+    static ref TIMING_DISTRIBUTION_METRICS: ConcurrentHandleMap<TimingDistributionMetric> =
+        ConcurrentHandleMap::new();
+    static ref START_TIME: Instant = Instant::now();
+    static ref APPLICATION_ID: Mutex<String> = Mutex::new(String::new());
+    /// Each registered ping's `send_if_empty` flag, keyed by name, so
+    /// `glean_submit_ping` can honor it without needing that detail back out
+    /// of `glean_core::ping::PingType`.
+    static ref PING_SEND_IF_EMPTY: Mutex<HashMap<String, bool>> = Mutex::new(HashMap::new());
+}
+
+/// Guards one-time-per-process work in `glean_initialize`: registering the
+/// built-in pings and reloading pending pings from disk. A second
+/// `glean_initialize` call in the same process must not re-register pings or
+/// re-enqueue files already sitting in `UPLOAD_QUEUE`.
+static INIT: Once = Once::new();
+
+/// Parses a JSON array of strings (e.g. `["key1", "key2"]`) coming in from the host
+/// language, used both for `send_in_pings` and for the event allowed extra-key list.
+fn parse_json_string_array(raw: FfiStr) -> Result<Vec<String>, String> {
+    serde_json::from_str(raw.as_str())
+        .map_err(|e| format!("Invalid JSON string array '{}': {}", raw.as_str(), e))
+}
+
+/// Maps the FFI's `i32` lifetime constant (Ping=0, Application=1, User=2) onto `Lifetime`.
+fn lifetime_from_i32(lifetime: i32) -> Result<Lifetime, String> {
+    match lifetime {
+        0 => Ok(Lifetime::Ping),
+        1 => Ok(Lifetime::Application),
+        2 => Ok(Lifetime::User),
+        e => Err(format!("Unknown lifetime value: {}", e)),
+    }
+}
+
+/// The built-in pings every metric's `send_in_pings` could already reference
+/// (e.g. the long-standing `"core"` default) before `glean_new_ping_type`
+/// existed. These must be registered explicitly now that `glean_ping_collect`
+/// and `glean_submit_ping` reject unregistered ping names, or collection for
+/// every metric still on the old default would silently break.
+const BUILTIN_PINGS: &[(&str, bool)] = &[
+    ("core", true),
+    ("metrics", true),
+    ("baseline", true),
+    // Unlike the other built-ins, an "events" ping with nothing recorded carries no
+    // useful payload, so it shouldn't be sent (and queued for upload) on every cycle.
+    ("events", false),
+];
+
+fn register_builtin_pings() {
+    let glean = Glean::singleton();
+    let mut send_if_empty = PING_SEND_IF_EMPTY.lock().unwrap();
+    for (name, ping_send_if_empty) in BUILTIN_PINGS {
+        let ping_type = PingType::new((*name).to_string(), true, *ping_send_if_empty);
+        glean.register_ping_type(&ping_type);
+        send_if_empty.insert((*name).to_string(), *ping_send_if_empty);
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn glean_initialize(data_dir: FfiStr) {
+pub extern "C" fn glean_initialize(data_dir: FfiStr, application_id: FfiStr) {
     #[cfg(target_os = "android")]
     {
         let _ = std::panic::catch_unwind(|| {
@@ -28,7 +91,18 @@ pub extern "C" fn glean_initialize(data_dir: FfiStr) {
     }
 
     let data_dir = data_dir.into_string();
+    *APPLICATION_ID.lock().unwrap() = application_id.into_string();
     Glean::singleton().initialize(&data_dir);
+
+    INIT.call_once(|| {
+        register_builtin_pings();
+        upload::restore_pending_pings(&data_dir);
+
+        // Force `START_TIME` to initialize here rather than lazily on the first
+        // `glean_event_record`, so event timestamps are actually relative to init.
+        lazy_static::initialize(&START_TIME);
+    });
+
     log::info!("Glean.rs initialized");
 }
 
@@ -52,15 +126,18 @@ pub extern "C" fn glean_set_upload_enabled(flag: u8) {
 pub extern "C" fn glean_new_boolean_metric(
     name: FfiStr,
     category: FfiStr,
+    send_in_pings: FfiStr,
+    lifetime: i32,
     err: &mut ExternError,
 ) -> u64 {
-    BOOLEAN_METRICS.insert_with_output(err, || {
-        BooleanMetric::new(CommonMetricData {
+    BOOLEAN_METRICS.insert_with_result(err, || -> Result<_, String> {
+        Ok(BooleanMetric::new(CommonMetricData {
             name: name.into_string(),
             category: category.into_string(),
-            send_in_pings: vec!["core".into()],
+            send_in_pings: parse_json_string_array(send_in_pings)?,
+            lifetime: lifetime_from_i32(lifetime)?,
             ..Default::default()
-        })
+        }))
     })
 }
 
@@ -68,15 +145,18 @@ pub extern "C" fn glean_new_boolean_metric(
 pub extern "C" fn glean_new_string_metric(
     name: FfiStr,
     category: FfiStr,
+    send_in_pings: FfiStr,
+    lifetime: i32,
     err: &mut ExternError,
 ) -> u64 {
-    STRING_METRICS.insert_with_output(err, || {
-        StringMetric::new(CommonMetricData {
+    STRING_METRICS.insert_with_result(err, || -> Result<_, String> {
+        Ok(StringMetric::new(CommonMetricData {
             name: name.into_string(),
             category: category.into_string(),
-            send_in_pings: vec!["core".into()],
+            send_in_pings: parse_json_string_array(send_in_pings)?,
+            lifetime: lifetime_from_i32(lifetime)?,
             ..Default::default()
-        })
+        }))
     })
 }
 
@@ -84,15 +164,18 @@ pub extern "C" fn glean_new_string_metric(
 pub extern "C" fn glean_new_counter_metric(
     name: FfiStr,
     category: FfiStr,
+    send_in_pings: FfiStr,
+    lifetime: i32,
     err: &mut ExternError,
 ) -> u64 {
-    COUNTER_METRICS.insert_with_output(err, || {
-        CounterMetric::new(CommonMetricData {
+    COUNTER_METRICS.insert_with_result(err, || -> Result<_, String> {
+        Ok(CounterMetric::new(CommonMetricData {
             name: name.into_string(),
             category: category.into_string(),
-            send_in_pings: vec!["core".into()],
+            send_in_pings: parse_json_string_array(send_in_pings)?,
+            lifetime: lifetime_from_i32(lifetime)?,
             ..Default::default()
-        })
+        }))
     })
 }
 
@@ -103,13 +186,355 @@ pub extern "C" fn glean_counter_add(metric_id: u64, amount: u64, error: &mut Ext
     })
 }
 
+#[no_mangle]
+pub extern "C" fn glean_new_event_metric(
+    name: FfiStr,
+    category: FfiStr,
+    send_in_pings: FfiStr,
+    lifetime: i32,
+    allowed_extra_keys: FfiStr,
+    err: &mut ExternError,
+) -> u64 {
+    EVENT_METRICS.insert_with_result(err, || -> Result<_, String> {
+        Ok(EventMetric::new(
+            CommonMetricData {
+                name: name.into_string(),
+                category: category.into_string(),
+                send_in_pings: parse_json_string_array(send_in_pings)?,
+                lifetime: lifetime_from_i32(lifetime)?,
+                ..Default::default()
+            },
+            parse_json_string_array(allowed_extra_keys)?,
+        ))
+    })
+}
+
+/// Decodes one extra key/value pair off the FFI boundary, rejecting invalid
+/// UTF-8 instead of lossy-decoding it so a malformed extra never silently
+/// corrupts the recorded event.
+fn decode_extra_pair(key: &CStr, value: &CStr) -> Result<(String, String), String> {
+    let key = key
+        .to_str()
+        .map_err(|e| format!("Invalid UTF-8 in extra key: {}", e))?
+        .to_owned();
+    let value = value
+        .to_str()
+        .map_err(|e| format!("Invalid UTF-8 in extra value: {}", e))?
+        .to_owned();
+    Ok((key, value))
+}
+
+/// Records an event, along with its extra keys and values.
+///
+/// `extra_keys` and `extra_values` are parallel C string arrays of length `extra_len`,
+/// decoded here and handed to `EventMetric::record` as a `HashMap`. Rejection of
+/// unknown keys (i.e. not in the metric's declared allowed list, set up in
+/// `glean_new_event_metric`) happens inside `glean_core::metrics::EventMetric::record`
+/// itself, which returns it through this function's `ExternError` like any other
+/// failure from `metric.record`. The event timestamp is monotonic, measured in
+/// milliseconds since `glean_initialize` was first called, so pings can later
+/// reconstruct the order events occurred in.
+///
+/// # Safety
+///
+/// `extra_keys` and `extra_values` must each point to `extra_len` valid,
+/// null-terminated C strings.
+#[no_mangle]
+pub unsafe extern "C" fn glean_event_record(
+    metric_id: u64,
+    extra_keys: *const *const c_char,
+    extra_values: *const *const c_char,
+    extra_len: i32,
+    error: &mut ExternError,
+) {
+    EVENT_METRICS.call_with_result(error, metric_id, |metric| -> Result<(), String> {
+        if !Glean::singleton().is_initialized() {
+            return Err(
+                "glean_event_record called before glean_initialize: START_TIME is not yet set"
+                    .into(),
+            );
+        }
+
+        let mut extra = HashMap::new();
+        for i in 0..extra_len as isize {
+            let key = CStr::from_ptr(*extra_keys.offset(i));
+            let value = CStr::from_ptr(*extra_values.offset(i));
+            let (key, value) = decode_extra_pair(key, value)?;
+            extra.insert(key, value);
+        }
+
+        let timestamp = START_TIME.elapsed().as_millis() as u64;
+        metric.record(timestamp, extra)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn glean_new_timing_distribution_metric(
+    name: FfiStr,
+    category: FfiStr,
+    send_in_pings: FfiStr,
+    lifetime: i32,
+    err: &mut ExternError,
+) -> u64 {
+    TIMING_DISTRIBUTION_METRICS.insert_with_result(err, || -> Result<_, String> {
+        Ok(TimingDistributionMetric::new(CommonMetricData {
+            name: name.into_string(),
+            category: category.into_string(),
+            send_in_pings: parse_json_string_array(send_in_pings)?,
+            lifetime: lifetime_from_i32(lifetime)?,
+            ..Default::default()
+        }))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn glean_timing_distribution_accumulate(
+    metric_id: u64,
+    sample_nanos: u64,
+    error: &mut ExternError,
+) {
+    TIMING_DISTRIBUTION_METRICS.call_with_output(error, metric_id, |metric| {
+        metric.accumulate(sample_nanos);
+    })
+}
+
+/// Returns the metric's current snapshot as a JSON string of the form
+/// `{"sum": ..., "count": ..., "values": {"<bucket_lower_bound>": <count>, ...}}`,
+/// for use by host-language tests that want to assert on the collected buckets.
+#[no_mangle]
+pub extern "C" fn glean_timing_distribution_snapshot(
+    metric_id: u64,
+    error: &mut ExternError,
+) -> *mut c_char {
+    TIMING_DISTRIBUTION_METRICS.call_with_result(error, metric_id, |metric| {
+        serde_json::to_string(&metric.get_snapshot()).map_err(|e| e.to_string())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn glean_new_ping_type(
+    ping_name: FfiStr,
+    include_client_id: u8,
+    send_if_empty: u8,
+    err: &mut ExternError,
+) -> u64 {
+    let ping_name = ping_name.into_string();
+    PING_SEND_IF_EMPTY
+        .lock()
+        .unwrap()
+        .insert(ping_name.clone(), send_if_empty != 0);
+    PING_TYPES.insert_with_output(err, || {
+        PingType::new(ping_name, include_client_id != 0, send_if_empty != 0)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn glean_register_ping_type(ping_type_handle: u64, err: &mut ExternError) {
+    PING_TYPES.call_with_output(err, ping_type_handle, |ping_type| {
+        Glean::singleton().register_ping_type(ping_type);
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn glean_ping_collect(ping_name: FfiStr, error: &mut ExternError) -> *mut c_char {
-    call_with_output(error, || {
+    call_with_result(error, || {
+        let ping_name = ping_name.into_string();
+        if Glean::singleton().get_ping_by_name(&ping_name).is_none() {
+            return Err(format!("No ping type registered for name '{}'", ping_name));
+        }
+
+        let ping_maker = glean_core::ping::PingMaker::new();
+        Ok(ping_maker.collect_string(&ping_name))
+    })
+}
+
+/// Whether a ping payload has nothing worth reporting: no metrics recorded,
+/// and (for event pings) no events recorded either.
+fn ping_is_empty(body: &str) -> bool {
+    let parsed: serde_json::Value = match serde_json::from_str(body) {
+        Ok(parsed) => parsed,
+        // An unparseable payload isn't something we can prove is empty.
+        Err(_) => return false,
+    };
+    let no_metrics = match parsed.get("metrics").and_then(|m| m.as_object()) {
+        Some(metrics) => metrics.is_empty(),
+        None => true,
+    };
+    let no_events = match parsed.get("events").and_then(|e| e.as_array()) {
+        Some(events) => events.is_empty(),
+        None => true,
+    };
+    no_metrics && no_events
+}
+
+/// Whether a collected ping should actually be queued for upload: pings
+/// registered with `send_if_empty = false` (e.g. the built-in `"events"`
+/// ping) are dropped instead of enqueued when they have nothing to report.
+fn should_submit_ping(body: &str, send_if_empty: bool) -> bool {
+    send_if_empty || !ping_is_empty(body)
+}
+
+/// Collects the named ping and queues it for upload, persisting its payload
+/// to disk under the `data_dir` passed to `glean_initialize` so it survives
+/// the process being killed before upload completes. Returns `1` if the ping
+/// was queued and durably persisted, `0` if it was empty and registered with
+/// `send_if_empty = false`. Like `glean_ping_collect`, an unregistered ping
+/// name is signaled through `error` rather than returned as `0`, and so are
+/// failures to persist to disk.
+#[no_mangle]
+pub extern "C" fn glean_submit_ping(ping_name: FfiStr, error: &mut ExternError) -> u8 {
+    call_with_result(error, || -> Result<bool, String> {
+        let ping_name = ping_name.into_string();
+        let glean = Glean::singleton();
+        if glean.get_ping_by_name(&ping_name).is_none() {
+            return Err(format!("No ping type registered for name '{}'", ping_name));
+        }
+
         let ping_maker = glean_core::ping::PingMaker::new();
-        ping_maker.collect_string(ping_name.as_str())
+        let body = ping_maker.collect_string(&ping_name);
+
+        let send_if_empty = PING_SEND_IF_EMPTY
+            .lock()
+            .unwrap()
+            .get(&ping_name)
+            .copied()
+            .unwrap_or(true);
+        if !should_submit_ping(&body, send_if_empty) {
+            return Ok(false);
+        }
+
+        let application_id = APPLICATION_ID.lock().unwrap().clone();
+        upload::enqueue_ping(&application_id, &glean.get_data_path(), &ping_name, body).map_err(
+            |e| {
+                log::error!("Failed to persist ping '{}' for upload: {}", ping_name, e);
+                format!("Failed to persist ping '{}' for upload: {}", ping_name, e)
+            },
+        )?;
+        Ok(true)
+    })
+}
+
+/// Hands the oldest queued ping to the host's network layer, as a JSON object
+/// `{"path": ..., "body": ...}`, or `{"done": true}` once the queue is empty.
+#[no_mangle]
+pub extern "C" fn glean_get_upload_task(error: &mut ExternError) -> *mut c_char {
+    call_with_output(error, || match upload::next_task() {
+        Some((path, body)) => serde_json::json!({ "path": path, "body": body }).to_string(),
+        None => serde_json::json!({ "done": true }).to_string(),
     })
 }
 
+/// Reports the outcome of the upload attempt for the ping last handed out by
+/// `glean_get_upload_task`: `result` is the upload's HTTP status code, or `0`
+/// if the host's network layer never got a response (timeout, DNS failure,
+/// no connectivity). 2xx removes it from the queue, 5xx and `0` leave it for
+/// retry, and anything else discards it as unrecoverable.
+#[no_mangle]
+pub extern "C" fn glean_upload_task_done(result: u16) {
+    // `task_done` locks `UPLOAD_QUEUE`, which is also touched by `enqueue_ping`
+    // and `next_task`; catch a panic here rather than let it unwind across the
+    // FFI boundary if the mutex is ever poisoned.
+    let _ = std::panic::catch_unwind(|| upload::task_done(result));
+}
+
 define_handle_map_deleter!(BOOLEAN_METRICS, glean_destroy_boolean_metric);
-define_string_destructor!(glean_str_free);
\ No newline at end of file
+define_handle_map_deleter!(STRING_METRICS, glean_destroy_string_metric);
+define_handle_map_deleter!(COUNTER_METRICS, glean_destroy_counter_metric);
+define_handle_map_deleter!(PING_TYPES, glean_destroy_ping_type);
+define_handle_map_deleter!(
+    TIMING_DISTRIBUTION_METRICS,
+    glean_destroy_timing_distribution_metric
+);
+define_handle_map_deleter!(EVENT_METRICS, glean_destroy_event_metric);
+define_string_destructor!(glean_str_free);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::CString;
+
+    fn ffi_str(s: &str) -> FfiStr<'static> {
+        // Leak the CString so the FfiStr borrow (tied to the 'static lifetime
+        // above) stays valid for the duration of the test.
+        let cstring = Box::leak(Box::new(CString::new(s).unwrap()));
+        FfiStr::from_cstr(cstring)
+    }
+
+    #[test]
+    fn parse_json_string_array_accepts_a_json_array_of_strings() {
+        assert_eq!(
+            parse_json_string_array(ffi_str(r#"["a", "b"]"#)).unwrap(),
+            vec!["a".to_string(), "b".to_string()]
+        );
+        assert_eq!(
+            parse_json_string_array(ffi_str("[]")).unwrap(),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn parse_json_string_array_rejects_malformed_json() {
+        assert!(parse_json_string_array(ffi_str("not json")).is_err());
+        assert!(parse_json_string_array(ffi_str(r#"["a", 1]"#)).is_err());
+    }
+
+    #[test]
+    fn lifetime_from_i32_maps_known_constants() {
+        assert_eq!(lifetime_from_i32(0).unwrap(), Lifetime::Ping);
+        assert_eq!(lifetime_from_i32(1).unwrap(), Lifetime::Application);
+        assert_eq!(lifetime_from_i32(2).unwrap(), Lifetime::User);
+    }
+
+    #[test]
+    fn lifetime_from_i32_rejects_unknown_values() {
+        assert!(lifetime_from_i32(3).is_err());
+        assert!(lifetime_from_i32(-1).is_err());
+    }
+
+    #[test]
+    fn decode_extra_pair_accepts_valid_utf8() {
+        let key = CString::new("key1").unwrap();
+        let value = CString::new("value1").unwrap();
+        assert_eq!(
+            decode_extra_pair(&key, &value).unwrap(),
+            ("key1".to_string(), "value1".to_string())
+        );
+    }
+
+    #[test]
+    fn decode_extra_pair_rejects_invalid_utf8_key_or_value() {
+        let valid = CString::new("key1").unwrap();
+        let invalid = CString::new(vec![0xff, 0xfe]).unwrap();
+
+        assert!(decode_extra_pair(&invalid, &valid).is_err());
+        assert!(decode_extra_pair(&valid, &invalid).is_err());
+    }
+
+    #[test]
+    fn ping_is_empty_true_when_no_metrics_or_events() {
+        assert!(ping_is_empty(r#"{"ping_info": {}}"#));
+        assert!(ping_is_empty(r#"{"ping_info": {}, "metrics": {}, "events": []}"#));
+    }
+
+    #[test]
+    fn ping_is_empty_false_when_metrics_or_events_present() {
+        assert!(!ping_is_empty(
+            r#"{"metrics": {"counter": {"cat.metric": 1}}}"#
+        ));
+        assert!(!ping_is_empty(r#"{"events": [{"category": "cat"}]}"#));
+    }
+
+    #[test]
+    fn should_submit_ping_always_submits_when_send_if_empty() {
+        assert!(should_submit_ping(r#"{"ping_info": {}}"#, true));
+    }
+
+    #[test]
+    fn should_submit_ping_skips_empty_ping_when_not_send_if_empty() {
+        assert!(!should_submit_ping(r#"{"ping_info": {}}"#, false));
+        assert!(should_submit_ping(
+            r#"{"events": [{"category": "cat"}]}"#,
+            false
+        ));
+    }
+}
\ No newline at end of file
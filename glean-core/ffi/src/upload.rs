@@ -0,0 +1,232 @@
+//! A small durable queue for collected pings awaiting upload.
+//!
+//! Each submitted ping is written to `<data_dir>/pending_pings/<ping_name>/<document_id>`
+//! before being handed off, so it survives the process being killed before the
+//! host's network layer gets to it. `restore_pending_pings` reloads anything left
+//! over from a previous run on the next `glean_initialize`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use uuid::Uuid;
+
+/// A ping collected and waiting to be handed to the host's network layer.
+pub struct PendingPing {
+    pub path: String,
+    pub body: String,
+    file_path: PathBuf,
+}
+
+lazy_static! {
+    static ref UPLOAD_QUEUE: Mutex<Vec<PendingPing>> = Mutex::new(Vec::new());
+}
+
+fn pings_dir(data_dir: &str) -> PathBuf {
+    Path::new(data_dir).join("pending_pings")
+}
+
+/// Writes `contents` to `dir/file_name` via a tmp-file-then-rename so a
+/// process killed mid-write never leaves a partially-written file under its
+/// real name. Creates `dir` if it doesn't exist yet.
+fn write_pending_ping_file(dir: &Path, file_name: &str, contents: &str) -> std::io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let file_path = dir.join(file_name);
+    let tmp_path = file_path.with_extension("tmp");
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, &file_path)?;
+    Ok(file_path)
+}
+
+/// Writes a freshly-collected ping's payload to disk and enqueues it for upload.
+pub fn enqueue_ping(
+    application_id: &str,
+    data_dir: &str,
+    ping_name: &str,
+    body: String,
+) -> std::io::Result<()> {
+    let document_id = Uuid::new_v4().to_string();
+    let submission_path = format!(
+        "/submit/{}/{}/1/{}",
+        application_id, ping_name, document_id
+    );
+
+    let dir = pings_dir(data_dir).join(ping_name);
+    let contents = format!("{}\n{}", submission_path, body);
+    let file_path = write_pending_ping_file(&dir, &document_id, &contents)?;
+
+    UPLOAD_QUEUE.lock().unwrap().push(PendingPing {
+        path: submission_path,
+        body,
+        file_path,
+    });
+    Ok(())
+}
+
+/// Scans `root` for pings left over on disk, ordered by file modification
+/// time so `task_done`'s "oldest first" retry order still holds across a
+/// restart, since `fs::read_dir` itself makes no such guarantee. Stray
+/// `.tmp` files (left over from a process killed mid-write-before-rename,
+/// whose finished ping, if any, landed under its real name instead) are
+/// deleted rather than restored.
+fn scan_pending_pings(root: &Path) -> Vec<PendingPing> {
+    let ping_dirs = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut restored = Vec::new();
+    for ping_dir in ping_dirs.flatten() {
+        let entries = match fs::read_dir(ping_dir.path()) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            if entry.path().extension().and_then(|ext| ext.to_str()) == Some("tmp") {
+                let _ = fs::remove_file(entry.path());
+                continue;
+            }
+            let contents = match fs::read_to_string(entry.path()) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            let modified = entry.metadata().and_then(|m| m.modified()).ok();
+            if let Some((path, body)) = contents.split_once('\n') {
+                restored.push((
+                    modified,
+                    PendingPing {
+                        path: path.to_string(),
+                        body: body.to_string(),
+                        file_path: entry.path(),
+                    },
+                ));
+            }
+        }
+    }
+
+    restored.sort_by_key(|(modified, _)| *modified);
+    restored.into_iter().map(|(_, ping)| ping).collect()
+}
+
+/// Reloads pings left over on disk from a previous run, so telemetry collected
+/// before an unclean shutdown isn't dropped on the floor.
+pub fn restore_pending_pings(data_dir: &str) {
+    let restored = scan_pending_pings(&pings_dir(data_dir));
+    UPLOAD_QUEUE.lock().unwrap().extend(restored);
+}
+
+/// Returns the oldest queued ping without removing it, or `None` if the queue
+/// is empty. The caller reports back via [`task_done`] once it knows the
+/// outcome of the upload attempt.
+pub fn next_task() -> Option<(String, String)> {
+    UPLOAD_QUEUE
+        .lock()
+        .unwrap()
+        .first()
+        .map(|ping| (ping.path.clone(), ping.body.clone()))
+}
+
+/// Whether an upload outcome of `status` should be retried later rather than
+/// discarded: `5xx` and `0` (the host's sentinel for a request that never got
+/// a response at all, e.g. no connectivity, a timeout, or a DNS failure) mean
+/// the ping was never rejected by a server, so it stays queued. Anything else
+/// (`2xx`, or a `4xx` the server will never accept) is resolved for good.
+fn should_retry(status: u16) -> bool {
+    status == 0 || (500..600).contains(&status)
+}
+
+/// Resolves the oldest queued ping given the upload's outcome: 2xx removes it
+/// for good, 5xx leaves it queued for a later retry, `0` is treated the same
+/// as 5xx, and anything else (e.g. 4xx) discards it.
+pub fn task_done(status: u16) {
+    let mut queue = UPLOAD_QUEUE.lock().unwrap();
+    if queue.is_empty() {
+        return;
+    }
+
+    if should_retry(status) {
+        // Leave it in place (at the front) so it's retried before newer pings.
+        return;
+    }
+
+    let ping = queue.remove(0);
+    if let Err(e) = fs::remove_file(&ping.file_path) {
+        // The ping is already off the in-memory queue at this point; if the
+        // file survives, the next `restore_pending_pings` will re-enqueue and
+        // re-upload an already-acknowledged ping.
+        log::warn!(
+            "Failed to remove uploaded ping file '{}': {}",
+            ping.file_path.display(),
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn temp_subdir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("glean_upload_test_{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn write_pending_ping_file_renames_into_place_with_no_tmp_left_behind() {
+        let dir = temp_subdir();
+
+        let file_path = write_pending_ping_file(&dir, "doc-1", "/submit/x\nbody").unwrap();
+
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "/submit/x\nbody");
+        assert!(!file_path.with_extension("tmp").exists());
+        let leftover: Vec<_> = fs::read_dir(&dir).unwrap().flatten().collect();
+        assert_eq!(leftover.len(), 1);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn scan_pending_pings_orders_by_mtime_and_drops_stray_tmp_files() {
+        let root = temp_subdir();
+        let ping_dir = root.join("my-ping");
+        fs::create_dir_all(&ping_dir).unwrap();
+
+        write_pending_ping_file(&ping_dir, "doc-1", "/submit/a/1\nfirst").unwrap();
+        thread::sleep(Duration::from_millis(20));
+        write_pending_ping_file(&ping_dir, "doc-2", "/submit/a/2\nsecond").unwrap();
+        fs::write(ping_dir.join("doc-3.tmp"), "unfinished write").unwrap();
+
+        let restored = scan_pending_pings(&root);
+
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored[0].path, "/submit/a/1");
+        assert_eq!(restored[0].body, "first");
+        assert_eq!(restored[1].path, "/submit/a/2");
+        assert_eq!(restored[1].body, "second");
+        assert!(!ping_dir.join("doc-3.tmp").exists());
+
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn scan_pending_pings_on_missing_directory_returns_empty() {
+        let root = std::env::temp_dir().join(format!("glean_upload_test_missing_{}", Uuid::new_v4()));
+        assert!(scan_pending_pings(&root).is_empty());
+    }
+
+    #[test]
+    fn should_retry_classifies_status_codes() {
+        assert!(should_retry(0));
+        assert!(should_retry(500));
+        assert!(should_retry(503));
+        assert!(should_retry(599));
+        assert!(!should_retry(200));
+        assert!(!should_retry(204));
+        assert!(!should_retry(404));
+        assert!(!should_retry(600));
+    }
+}